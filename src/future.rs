@@ -0,0 +1,216 @@
+//! Utilities for keeping the Sentry scope bound across `.await` points.
+//!
+//! The [`with_stack`](crate::scope::with_stack) machinery keys everything
+//! off a thread local, so a future that is polled on a work-stealing
+//! executor loses its breadcrumbs/tags/user whenever it resumes on a
+//! different worker thread than the one it started on.  [`bind_scope`]
+//! wraps a future so that, on every poll, the layers captured from the
+//! previous poll (or, the first time, a single snapshot of the top layer
+//! taken when `bind_scope` was called) are temporarily pushed onto the *currently
+//! executing* thread's stack, mirroring the way `tracing-futures`
+//! instruments futures across task boundaries.
+//!
+//! A single poll does not necessarily push exactly one layer: the inner
+//! future might itself call `push_scope()` and hold the resulting guard
+//! across an inner `.await`, in which case `poll` returns with an extra
+//! layer still open.  `ScopeFuture` tracks the stack depth before and
+//! after polling and captures *all* of the layers added during that poll
+//! (not just the one it pushed itself), restoring every one of them, in
+//! order, at the start of the next poll.  This way in-progress nested
+//! scopes are neither corrupted nor leaked onto the ambient thread stack
+//! once the future is no longer being polled.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use scope::{with_stack, StackLayer};
+
+/// A future that keeps the Sentry scope active for `.await` points that
+/// migrate across threads.
+///
+/// Constructed with [`bind_scope`].
+pub struct ScopeFuture<F> {
+    layers: Vec<StackLayer>,
+    inner: F,
+}
+
+/// Binds the current scope to a future.
+///
+/// The top `StackLayer` (the active client and scope) is snapshotted when
+/// this is called.  On every poll of the returned future, the layers
+/// captured from the previous poll are temporarily pushed back onto the
+/// polling thread's stack, so that `add_breadcrumb`/`configure_scope`
+/// calls made inside the future land in the scope that was active when
+/// the future was created, regardless of which executor thread actually
+/// drives the poll.
+pub fn bind_scope<F>(fut: F) -> ScopeFuture<F>
+where
+    F: Future,
+{
+    ScopeFuture {
+        layers: vec![with_stack(|stack| stack.top_layer())],
+        inner: fut,
+    }
+}
+
+impl<F> Future for ScopeFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: we only ever reborrow `inner` through its own pin; the
+        // struct is not `Unpin`-sensitive beyond that, as `layers` is not
+        // pinned data.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        // Restore the layers captured from the previous poll (or the
+        // initial snapshot) *before* taking a pin to `inner`: closures in
+        // this edition capture `this` as a whole, so any closure that uses
+        // `this` has to run before `inner`'s reborrow of `this.inner` starts.
+        let layers = this.layers.clone();
+        let len_before = with_stack(|stack| {
+            let len_before = stack.len();
+            for layer in layers {
+                stack.push_layer(layer);
+            }
+            len_before
+        });
+
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+
+        // Whatever is left on the stack above `len_before` once the poll
+        // returns belongs to this future (our own snapshot, plus any
+        // scopes the inner future pushed and is still holding open across
+        // an `.await`), not to whichever task runs on this thread next.
+        // Pop all of it back off and save it verbatim so the next poll
+        // restores exactly the state this one leaves behind, even if that
+        // is more than the single layer we started with.
+        struct PopGuard<'a> {
+            layers: &'a mut Vec<StackLayer>,
+            len_before: usize,
+        }
+        impl<'a> Drop for PopGuard<'a> {
+            fn drop(&mut self) {
+                let mut popped = with_stack(|stack| {
+                    let mut popped = Vec::new();
+                    while stack.len() > self.len_before {
+                        popped.push(stack.pop());
+                    }
+                    popped
+                });
+                popped.reverse();
+                *self.layers = popped;
+            }
+        }
+        let _pop = PopGuard {
+            layers: &mut this.layers,
+            len_before,
+        };
+
+        inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scope::{push_scope, ScopeGuard};
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn noop_waker() -> Waker {
+        fn no_op(_: *const ()) {}
+        fn clone(_: *const ()) -> RawWaker {
+            raw_waker()
+        }
+        fn raw_waker() -> RawWaker {
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+            RawWaker::new(::std::ptr::null(), &VTABLE)
+        }
+        unsafe { Waker::from_raw(raw_waker()) }
+    }
+
+    /// A future that returns `Pending` once (mutating the scope first),
+    /// then `Ready`.
+    struct TwoStep {
+        polled: bool,
+    }
+
+    impl Future for TwoStep {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            if !self.polled {
+                self.polled = true;
+                with_stack(|stack| stack.scope_mut().set_tag("seen", "yes"));
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    #[test]
+    fn bind_scope_persists_mutations_across_polls() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = bind_scope(TwoStep { polled: false });
+
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        assert_eq!(
+            fut.layers[0].scope().tags.get("seen").cloned(),
+            Some("yes".to_string())
+        );
+
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(()));
+    }
+
+    /// A future that, on its first poll, pushes a scope with `push_scope`
+    /// and holds the guard in `self` across the `Pending` return — the
+    /// same shape as `let _g = push_scope(); other.await;` in an async fn.
+    struct NestedScope {
+        started: bool,
+        _guard: Option<ScopeGuard>,
+    }
+
+    impl Future for NestedScope {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            if !self.started {
+                self.started = true;
+                self._guard = Some(push_scope());
+                with_stack(|stack| stack.scope_mut().set_tag("nested", "yes"));
+                Poll::Pending
+            } else {
+                // Like the end of an async fn body, release the guard
+                // before completing.
+                self._guard = None;
+                Poll::Ready(())
+            }
+        }
+    }
+
+    #[test]
+    fn bind_scope_does_not_leak_a_scope_held_across_await() {
+        let depth_before = with_stack(|stack| stack.len());
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = bind_scope(NestedScope {
+            started: false,
+            _guard: None,
+        });
+
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Pending);
+        // The nested `push_scope` layer must be captured along with our
+        // own, not left on the ambient stack for unrelated code running
+        // on this thread between polls.
+        assert_eq!(with_stack(|stack| stack.len()), depth_before);
+        assert_eq!(fut.layers.len(), 2);
+
+        assert_eq!(Pin::new(&mut fut).poll(&mut cx), Poll::Ready(()));
+        assert_eq!(with_stack(|stack| stack.len()), depth_before);
+    }
+}
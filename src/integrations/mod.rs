@@ -0,0 +1,3 @@
+//! Integrations with third party frameworks and libraries.
+
+pub mod tracing;
@@ -0,0 +1,276 @@
+//! Integration with the `tracing` crate.
+//!
+//! This builds a `tracing_subscriber::Layer` on top of the `registry`/
+//! `env-filter` stack that drives the `Scope`/`Stack` machinery in
+//! [`scope`](crate::scope) automatically: spans become scopes and events
+//! become breadcrumbs.
+//!
+//! Because `tracing` spans can be entered and exited on a different thread
+//! than the one that created them (work-stealing executors move tasks
+//! around), the layer cannot rely on the ambient thread stack alone.
+//! Instead, `on_new_span` derives the span's `StackLayer` and stores it in
+//! the span's registry extensions without touching the live stack; only
+//! `on_enter`/`on_exit` actually push/pop it onto whichever thread the
+//! span is entered on, writing the (possibly mutated) layer back into the
+//! extensions on exit so it survives being re-entered later.
+
+use std::fmt;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Id, Level as TracingLevel, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use api::protocol::{Breadcrumb, Level, Value};
+use scope::{with_stack, Scope, StackLayer};
+
+/// A `tracing_subscriber::Layer` that maps spans to Sentry scopes and
+/// events to breadcrumbs.
+#[derive(Debug, Default)]
+pub struct SentryLayer;
+
+impl SentryLayer {
+    /// Creates a new layer.
+    pub fn new() -> Self {
+        SentryLayer
+    }
+}
+
+struct SpanData {
+    layer: StackLayer,
+}
+
+fn tracing_level_to_breadcrumb_level(level: &TracingLevel) -> Level {
+    match *level {
+        TracingLevel::TRACE | TracingLevel::DEBUG => Level::Debug,
+        TracingLevel::INFO => Level::Info,
+        TracingLevel::WARN => Level::Warning,
+        TracingLevel::ERROR => Level::Error,
+    }
+}
+
+/// Collects recorded fields and stuffs them into the given scope.
+struct ScopeVisitor<'a> {
+    scope: &'a mut Scope,
+}
+
+impl<'a> Visit for ScopeVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.scope.set_tag(field.name(), value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.scope.set_tag(field.name(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.scope.set_tag(field.name(), value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.scope.set_tag(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.scope
+            .set_extra(field.name(), Value::from(format!("{:?}", value)));
+    }
+}
+
+/// Collects a single `message` field for use as a breadcrumb's message.
+#[derive(Default)]
+struct MessageVisitor {
+    message: Option<String>,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S> Layer<S> for SentryLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        // Build the span's layer from the current top of the stack, but do
+        // not push it: the span may sit open-but-not-entered for a while
+        // (e.g. while sibling spans are created on the same thread), and
+        // only `on_enter`/`on_exit` should make it the live layer.
+        let mut layer = with_stack(|stack| stack.top_layer());
+        attrs.record(&mut ScopeVisitor {
+            scope: layer.scope_mut(),
+        });
+
+        span.extensions_mut().insert(SpanData { layer });
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let extensions = span.extensions();
+        if let Some(data) = extensions.get::<SpanData>() {
+            with_stack(|stack| stack.push_layer(data.layer.clone()));
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        if span.extensions().get::<SpanData>().is_some() {
+            // Persist whatever was mutated while the span was entered (new
+            // breadcrumbs, tags, ...) so it survives being re-entered later.
+            let popped = with_stack(|stack| stack.pop());
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.layer = popped;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        span.extensions_mut().remove::<SpanData>();
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let breadcrumb = Breadcrumb {
+            message: visitor.message,
+            category: Some(event.metadata().target().to_string()),
+            level: tracing_level_to_breadcrumb_level(event.metadata().level()),
+            ..Default::default()
+        };
+
+        with_stack(|stack| stack.add_breadcrumb(breadcrumb));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::{event, info_span, Level as TLevel};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn run_with_layer<F: FnOnce()>(f: F) {
+        let subscriber = tracing_subscriber::registry().with(SentryLayer::new());
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    #[test]
+    fn span_fields_become_tags_while_entered() {
+        run_with_layer(|| {
+            let span = info_span!("outer", user_id = 42);
+            let _enter = span.enter();
+            assert_eq!(
+                with_stack(|stack| stack.scope_mut().tags.get("user_id").cloned()),
+                Some("42".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn tags_do_not_leak_after_the_span_exits() {
+        run_with_layer(|| {
+            {
+                let span = info_span!("outer", user_id = 42);
+                let _enter = span.enter();
+            }
+            assert_eq!(
+                with_stack(|stack| stack.scope_mut().tags.get("user_id").cloned()),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn an_open_but_unentered_span_does_not_leak_tags_to_a_sibling() {
+        run_with_layer(|| {
+            // Create (but do not enter) a span with a tag, then create and
+            // enter a sibling on the same thread.  Regression test for the
+            // bug fixed in b56c412, where `on_new_span` pushed onto the
+            // live stack instead of only deriving a layer, so a sibling
+            // span spuriously inherited it.
+            let _first = info_span!("first", first_tag = 1);
+
+            let second = info_span!("second", second_tag = 2);
+            let _enter = second.enter();
+
+            assert_eq!(
+                with_stack(|stack| stack.scope_mut().tags.get("first_tag").cloned()),
+                None
+            );
+            assert_eq!(
+                with_stack(|stack| stack.scope_mut().tags.get("second_tag").cloned()),
+                Some("2".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn nested_spans_inherit_parent_tags() {
+        run_with_layer(|| {
+            let outer = info_span!("outer");
+            let _outer_enter = outer.enter();
+            with_stack(|stack| stack.scope_mut().set_tag("outer_tag", "yes"));
+
+            let inner = info_span!("inner");
+            let _inner_enter = inner.enter();
+            assert_eq!(
+                with_stack(|stack| stack.scope_mut().tags.get("outer_tag").cloned()),
+                Some("yes".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn events_become_breadcrumbs_with_the_mapped_level() {
+        run_with_layer(|| {
+            let span = info_span!("outer");
+            let _enter = span.enter();
+            event!(TLevel::WARN, "something happened");
+
+            with_stack(|stack| {
+                assert_eq!(stack.scope_mut().breadcrumbs.len(), 1);
+                assert_eq!(
+                    stack.scope_mut().breadcrumbs.iter().last().unwrap().level,
+                    Level::Warning
+                );
+            });
+        });
+    }
+
+    #[test]
+    fn tags_set_while_entered_survive_being_re_entered() {
+        run_with_layer(|| {
+            let span = info_span!("outer");
+            {
+                let _enter = span.enter();
+                with_stack(|stack| stack.scope_mut().set_tag("set_once", "yes"));
+            }
+            {
+                let _enter = span.enter();
+                assert_eq!(
+                    with_stack(|stack| stack.scope_mut().tags.get("set_once").cloned()),
+                    Some("yes".to_string())
+                );
+            }
+        });
+    }
+}
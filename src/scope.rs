@@ -1,18 +1,22 @@
-use std::mem;
-use std::thread;
 use std::cell::RefCell;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
-use api::protocol::{Breadcrumb, User, Value};
+use api::protocol::{Breadcrumb, Context, Level, User, Value};
 use client::Client;
 
 use im;
 
+/// The number of breadcrumbs retained when no client is bound.
+const DEFAULT_MAX_BREADCRUMBS: usize = 100;
+
 lazy_static! {
-    static ref PROCESS_STACK: Mutex<Stack> = Mutex::new(Stack::for_process());
+    static ref PROCESS_HUB: Hub = Hub {
+        inner: Arc::new(Mutex::new(Stack::for_process())),
+    };
 }
 thread_local! {
-    static THREAD_STACK: RefCell<Stack> = RefCell::new(Stack::for_thread());
+    static THREAD_HUB: RefCell<Option<Hub>> = RefCell::new(None);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -28,7 +32,7 @@ pub struct Stack {
 }
 
 #[derive(PartialEq, Clone, Copy)]
-struct StackLayerToken(*const Stack, usize);
+pub(crate) struct StackLayerToken(*const Stack, usize);
 
 /// Holds contextual data for the current scope.
 ///
@@ -51,14 +55,31 @@ pub struct Scope {
     pub(crate) user: Option<Arc<User>>,
     pub(crate) extra: im::HashMap<String, Value>,
     pub(crate) tags: im::HashMap<String, String>,
+    pub(crate) contexts: im::HashMap<String, Context>,
+    pub(crate) fingerprint: Option<Arc<Vec<String>>>,
+    pub(crate) level: Option<Level>,
 }
 
 #[derive(Default, Debug, Clone)]
-struct StackLayer {
+pub(crate) struct StackLayer {
     client: Option<Arc<Client>>,
     scope: Scope,
 }
 
+impl StackLayer {
+    pub(crate) fn client(&self) -> Option<Arc<Client>> {
+        self.client.clone()
+    }
+
+    pub(crate) fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    pub(crate) fn scope_mut(&mut self) -> &mut Scope {
+        &mut self.scope
+    }
+}
+
 impl Stack {
     pub fn for_process() -> Stack {
         Stack {
@@ -67,14 +88,10 @@ impl Stack {
         }
     }
 
-    pub fn for_thread() -> Stack {
+    /// Derives a fresh thread stack from the top layer of `parent`.
+    fn for_thread_from(parent: &Stack) -> Stack {
         Stack {
-            layers: vec![
-                with_process_stack(|stack| StackLayer {
-                    client: stack.client(),
-                    scope: stack.scope_mut().clone(),
-                }),
-            ],
+            layers: vec![parent.top_layer()],
             ty: StackType::Thread,
         }
     }
@@ -84,11 +101,32 @@ impl Stack {
         self.layers.push(scope);
     }
 
-    pub fn pop(&mut self) {
+    /// Pushes an already constructed layer onto the stack.
+    ///
+    /// Unlike `push` this does not derive the new layer from the current
+    /// top of the stack.  It is used by integrations that capture a
+    /// `StackLayer` elsewhere (for instance on a different thread) and need
+    /// to temporarily install it as the active layer here.
+    pub(crate) fn push_layer(&mut self, layer: StackLayer) {
+        self.layers.push(layer);
+    }
+
+    /// Returns a clone of the currently active layer.
+    pub(crate) fn top_layer(&self) -> StackLayer {
+        self.layers[self.layers.len() - 1].clone()
+    }
+
+    /// Returns the number of layers currently on the stack.
+    pub(crate) fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Pops the current layer off the stack, returning it.
+    pub fn pop(&mut self) -> StackLayer {
         if self.layers.len() <= 1 {
             panic!("Pop from empty {:?} stack", self.ty)
         }
-        self.layers.pop().unwrap();
+        self.layers.pop().unwrap()
     }
 
     pub fn bind_client(&mut self, client: Arc<Client>) {
@@ -105,33 +143,119 @@ impl Stack {
         &mut self.layers[idx].scope
     }
 
-    fn token(&self) -> StackLayerToken {
+    /// Records a breadcrumb on the current scope, enforcing the bound
+    /// client's `max_breadcrumbs` limit (or `DEFAULT_MAX_BREADCRUMBS` if no
+    /// client is bound).
+    pub(crate) fn add_breadcrumb(&mut self, breadcrumb: Breadcrumb) {
+        let max_breadcrumbs = self
+            .client()
+            .map(|client| client.options().max_breadcrumbs)
+            .unwrap_or(DEFAULT_MAX_BREADCRUMBS);
+        let scope = self.scope_mut();
+        scope.add_breadcrumb(breadcrumb);
+        scope.trim_breadcrumbs(max_breadcrumbs);
+    }
+
+    pub(crate) fn token(&self) -> StackLayerToken {
         StackLayerToken(self as *const Stack, self.layers.len())
     }
 }
 
+/// A cloneable handle to a stack of scopes.
+///
+/// Previously which stack a thread used was decided implicitly: thread 0
+/// (detected by transmuting its `ThreadId`, which is undefined behavior
+/// that relies on an undocumented layout) shared the process stack
+/// directly, and every other thread got its own copy lazily.  `Hub` makes
+/// this explicit: `Hub::main()` returns a handle to the shared process
+/// stack, `Hub::current()` returns whichever hub is bound to the calling
+/// thread (the first time it is called on a thread that never bound one,
+/// binding `Hub::main()` itself on the main thread and a hub derived from
+/// it on any other thread), and `Hub::new_from_top` lets callers
+/// build a handle to hand off to a freshly spawned thread.
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Mutex<Stack>>,
+}
+
+/// Checks whether this is the main thread, by name rather than by
+/// transmuting its `ThreadId` (which relied on an undocumented layout and
+/// was undefined behavior).  The runtime names the main thread `"main"`;
+/// this is only used to decide which hub to lazily bind to a thread that
+/// never bound one explicitly, so a false negative here just means that
+/// thread gets its own forked hub instead of aliasing the process one.
+///
+/// This is weaker than the `ThreadId` check it replaces in the
+/// other direction too: a *false positive* is possible if some other
+/// thread (a thread pool, a test harness, an embedder) happens to also
+/// name one of its own threads `"main"`.  Such a thread would alias
+/// `Hub::main()` and share the real main thread's client/scope instead of
+/// getting an isolated stack.  Threads that care about this should not
+/// rely on the name-based default and should instead bind an explicit
+/// `Hub` with `Hub::new_from_top` and `bind_to_thread`.
 fn is_main_thread() -> bool {
-    let thread = thread::current();
-    let raw_id: u64 = unsafe { mem::transmute(thread.id()) };
-    raw_id == 0
+    thread::current().name() == Some("main")
 }
 
-fn with_process_stack<F, R>(f: F) -> R
-where
-    F: FnOnce(&mut Stack) -> R,
-{
-    f(&mut PROCESS_STACK.lock().unwrap())
+impl Hub {
+    /// Returns a handle to the shared process-wide stack.
+    pub fn main() -> Hub {
+        PROCESS_HUB.clone()
+    }
+
+    /// Returns the hub bound to the current thread.
+    ///
+    /// If no hub has been explicitly bound to this thread yet, this binds
+    /// one: the main thread aliases `Hub::main()` itself (the same shared
+    /// `Arc`, so code that configures the client/scope there, e.g.
+    /// `sentry::init()`, actually mutates the process hub), while every
+    /// other thread gets a private hub derived from `Hub::main()`'s
+    /// current top layer.
+    pub fn current() -> Hub {
+        THREAD_HUB.with(|slot| {
+            let mut slot = slot.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(if is_main_thread() {
+                    Hub::main()
+                } else {
+                    Hub::new_from_top(&Hub::main())
+                });
+            }
+            slot.as_ref().unwrap().clone()
+        })
+    }
+
+    /// Creates a new hub whose single stack layer is derived from the
+    /// current top layer of `other`.
+    pub fn new_from_top(other: &Hub) -> Hub {
+        let parent = other.inner.lock().unwrap();
+        Hub {
+            inner: Arc::new(Mutex::new(Stack::for_thread_from(&parent))),
+        }
+    }
+
+    /// Binds this hub as the current thread's hub.
+    ///
+    /// Use this to hand a hub created with `new_from_top` to a freshly
+    /// spawned thread so it starts out with the intended client and scope
+    /// instead of lazily deriving one from `Hub::main()`.
+    pub fn bind_to_thread(self) {
+        THREAD_HUB.with(|slot| *slot.borrow_mut() = Some(self));
+    }
+
+    fn with_stack<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Stack) -> R,
+    {
+        f(&mut self.inner.lock().unwrap())
+    }
 }
 
 pub fn with_stack<F, R>(f: F) -> R
 where
     F: FnOnce(&mut Stack) -> R,
 {
-    if is_main_thread() {
-        with_process_stack(f)
-    } else {
-        THREAD_STACK.with(|stack| f(&mut *stack.borrow_mut()))
-    }
+    Hub::current().with_stack(f)
 }
 
 /// Crate internal helper for working with clients and scopes.
@@ -153,6 +277,16 @@ where
 #[derive(Default)]
 pub struct ScopeGuard(Option<StackLayerToken>);
 
+impl ScopeGuard {
+    /// Constructs a guard from a raw token.
+    ///
+    /// For integrations that push a layer onto a stack themselves
+    /// (outside of `push_scope`) and need a guard that pops it again.
+    pub(crate) fn for_token(token: StackLayerToken) -> ScopeGuard {
+        ScopeGuard(Some(token))
+    }
+}
+
 impl Drop for ScopeGuard {
     fn drop(&mut self) {
         if let Some(token) = self.0 {
@@ -203,6 +337,26 @@ impl Scope {
         *self = Default::default();
     }
 
+    /// Records a new breadcrumb on the current scope.
+    pub(crate) fn add_breadcrumb(&mut self, breadcrumb: Breadcrumb) {
+        self.breadcrumbs.push_back(breadcrumb);
+    }
+
+    /// Evicts breadcrumbs from the front until at most `max_breadcrumbs`
+    /// remain.
+    ///
+    /// `breadcrumbs` is an `im::Vector`, a persistent structure that is
+    /// shared between cloned scopes via `Stack::push`.  Eviction therefore
+    /// has to produce a new vector rather than mutate the shared one in
+    /// place, so that parent layers on the stack keep their own (longer)
+    /// history intact.
+    pub(crate) fn trim_breadcrumbs(&mut self, max_breadcrumbs: usize) {
+        let len = self.breadcrumbs.len();
+        if len > max_breadcrumbs {
+            self.breadcrumbs = self.breadcrumbs.split_off(len - max_breadcrumbs);
+        }
+    }
+
     /// Sets the user for the current scope.
     pub fn set_user(&mut self, user: Option<User>) {
         self.user = user.map(Arc::new);
@@ -229,4 +383,94 @@ impl Scope {
         // annoyingly this needs a String :(
         self.extra = self.extra.remove(&key.to_string());
     }
-}
\ No newline at end of file
+
+    /// Sets a typed context to a specific value.
+    pub fn set_context(&mut self, key: &str, context: Context) {
+        self.contexts = self.contexts.insert(key.to_string(), context);
+    }
+
+    /// Removes a context.
+    pub fn remove_context(&mut self, key: &str) {
+        // annoyingly this needs a String :(
+        self.contexts = self.contexts.remove(&key.to_string());
+    }
+
+    /// Sets the fingerprint used to group events, overriding Sentry's
+    /// default grouping.
+    pub fn set_fingerprint(&mut self, fingerprint: Option<&[&str]>) {
+        self.fingerprint =
+            fingerprint.map(|fp| Arc::new(fp.iter().map(|s| (*s).to_string()).collect()));
+    }
+
+    /// Forces the level of events captured within this scope.
+    pub fn set_level(&mut self, level: Option<Level>) {
+        self.level = level;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_breadcrumbs_keeps_most_recent() {
+        let mut scope = Scope::default();
+        for i in 0..5 {
+            scope.add_breadcrumb(Breadcrumb {
+                message: Some(i.to_string()),
+                ..Default::default()
+            });
+        }
+        scope.trim_breadcrumbs(3);
+
+        let messages: Vec<_> = scope
+            .breadcrumbs
+            .iter()
+            .map(|crumb| crumb.message.clone().unwrap())
+            .collect();
+        assert_eq!(messages, vec!["2", "3", "4"]);
+    }
+
+    #[test]
+    fn trim_breadcrumbs_is_a_noop_under_the_limit() {
+        let mut scope = Scope::default();
+        scope.add_breadcrumb(Breadcrumb::default());
+        scope.trim_breadcrumbs(10);
+        assert_eq!(scope.breadcrumbs.len(), 1);
+    }
+
+    #[test]
+    fn hub_new_from_top_inherits_but_is_independent() {
+        let parent = Hub {
+            inner: Arc::new(Mutex::new(Stack::for_process())),
+        };
+        parent.with_stack(|stack| stack.scope_mut().set_tag("inherited", "yes"));
+
+        let child = Hub::new_from_top(&parent);
+        assert_eq!(
+            child
+                .with_stack(|stack| stack.scope_mut().tags.get("inherited").cloned()),
+            Some("yes".to_string())
+        );
+
+        child.with_stack(|stack| stack.scope_mut().set_tag("child_only", "yes"));
+        assert_eq!(
+            parent.with_stack(|stack| stack.scope_mut().tags.get("child_only").cloned()),
+            None
+        );
+    }
+
+    #[test]
+    fn hub_bind_to_thread_round_trips() {
+        let hub = Hub {
+            inner: Arc::new(Mutex::new(Stack::for_process())),
+        };
+        hub.with_stack(|stack| stack.scope_mut().set_tag("bound", "yes"));
+        hub.clone().bind_to_thread();
+
+        assert_eq!(
+            Hub::current().with_stack(|stack| stack.scope_mut().tags.get("bound").cloned()),
+            Some("yes".to_string())
+        );
+    }
+}